@@ -1,7 +1,8 @@
 use std::{
+    cmp::Reverse,
     collections::HashMap,
     io::{self, BufRead, BufReader, Write},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     process::{Command, Stdio},
     thread,
 };
@@ -19,10 +20,136 @@ pub struct Opt {
     )]
     pub directories: bool,
 
+    #[structopt(
+        short,
+        long,
+        env("FATFILES_JOBS"),
+        help("Number of parallel git cat-file workers (defaults to the detected CPU count).")
+    )]
+    pub jobs: Option<usize>,
+
+    #[structopt(
+        long,
+        help("In --directories mode, collapse everything below this depth into its ancestor.")
+    )]
+    pub max_depth: Option<usize>,
+
+    #[structopt(
+        short,
+        long,
+        help("Report logical vs packed (on-disk) bytes per path and the percent saved.")
+    )]
+    pub unique: bool,
+
+    #[structopt(
+        short,
+        long,
+        default_value("human"),
+        help("Output format: human, json, or csv.")
+    )]
+    pub format: Format,
+
+    #[structopt(short, long, help("Only show the N largest entries."))]
+    pub top: Option<usize>,
+
+    #[structopt(long, help("Suppress entries smaller than this size, e.g. 10MB."))]
+    pub min_size: Option<ByteSize>,
+
+    #[structopt(
+        long,
+        help("Cap peak memory by sizing objects in bounded batches, e.g. 256MB.")
+    )]
+    pub max_mem: Option<ByteSize>,
+
     #[structopt(help("Optional: only show the size info about certain paths."))]
     pub paths: Vec<String>,
 }
 
+/// Output format for the flat size listing.
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Human,
+    Json,
+    Csv,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Format::Human),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            other => Err(format!("unknown format '{}' (want human, json, or csv)", other)),
+        }
+    }
+}
+
+/// A node in the directory tree built for `--directories` mode.
+///
+/// Every committed blob is inserted at its leaf; `rollup` then sums each
+/// subtree so a directory's reported size covers all of its descendants.
+#[derive(Default)]
+struct Directory {
+    name: String,
+    size: u64,
+    children: HashMap<String, Directory>,
+}
+
+impl Directory {
+    fn new(name: String) -> Self {
+        Directory {
+            name,
+            size: 0,
+            children: HashMap::new(),
+        }
+    }
+
+    /// Add `size` bytes at the leaf named by the remaining path `components`.
+    fn insert(&mut self, components: &[Component], size: u64) {
+        match components.split_first() {
+            None => self.size += size,
+            Some((head, rest)) => {
+                let name = head.as_os_str().to_string_lossy().into_owned();
+                self.children
+                    .entry(name.clone())
+                    .or_insert_with(|| Directory::new(name))
+                    .insert(rest, size);
+            }
+        }
+    }
+
+    /// Roll descendant sizes up so each node's size includes its whole subtree.
+    fn rollup(&mut self) -> u64 {
+        let mut total = self.size;
+        for child in self.children.values_mut() {
+            total += child.rollup();
+        }
+        self.size = total;
+        self.size
+    }
+
+    /// Print the children as an indented tree, largest first at each level.
+    /// Recursion stops once `depth` reaches `max_depth`, collapsing deeper
+    /// nodes into the ancestor whose rolled-up size already accounts for them.
+    fn print(&self, depth: usize, max_depth: Option<usize>) {
+        let mut children: Vec<&Directory> = self.children.values().collect();
+        children.sort_by_key(|child| Reverse(child.size));
+        for child in children {
+            println!(
+                "{:10}{}{}",
+                ByteSize(child.size),
+                "  ".repeat(depth),
+                child.name
+            );
+            if max_depth.is_none_or(|max| depth + 1 < max) {
+                child.print(depth + 1, max_depth);
+            }
+        }
+    }
+}
+
 /// The paths list is a filter. If empty, there is no filtering.
 /// Returns a map of object ID -> filename.
 fn get_revs_for_paths(paths: Vec<String>) -> HashMap<String, PathBuf> {
@@ -49,29 +176,137 @@ fn get_revs_for_paths(paths: Vec<String>) -> HashMap<String, PathBuf> {
     id_map
 }
 
+/// Logical (`%(objectsize)`) and packed (`%(objectsize:disk)`) size of a blob.
+#[derive(Clone, Copy, Default)]
+struct ObjectSize {
+    logical: u64,
+    disk: u64,
+}
+
+/// The number of parallel `git cat-file` workers to use when none is requested.
+fn default_jobs() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
 /// Returns a map of object ID to size.
-fn get_sizes_of_objects(ids: Vec<&String>) -> HashMap<String, u64> {
-    let mut process = Command::new("git")
+///
+/// The IDs are sharded into `jobs` roughly equal slices, each drained into its
+/// own `git cat-file` child process so large repos are not bottlenecked on a
+/// single pipe. The per-worker maps are merged once every shard has finished.
+fn get_sizes_of_objects(ids: Vec<&String>, jobs: usize) -> HashMap<String, ObjectSize> {
+    // copy data so each worker owns its slice
+    let ids: Vec<String> = ids.into_iter().cloned().collect();
+    if ids.is_empty() {
+        return HashMap::new();
+    }
+
+    // Never spawn more workers than there are IDs, and always at least one.
+    let jobs = jobs.max(1).min(ids.len());
+    let chunk_size = ids.len().div_ceil(jobs);
+
+    let workers: Vec<_> = ids
+        .chunks(chunk_size)
+        .map(|chunk| {
+            let chunk = chunk.to_vec();
+            thread::spawn(move || get_sizes_of_shard(chunk))
+        })
+        .collect();
+
+    let mut id_map = HashMap::new();
+    for worker in workers {
+        let partial = worker.join().expect("cat-file worker thread panicked.");
+        id_map.extend(partial);
+    }
+    id_map
+}
+
+/// Rough per-object cost (ID string plus its path) used to turn a `--max-mem`
+/// byte budget into a count of objects that may be buffered in flight at once.
+const EST_BYTES_PER_OBJECT: usize = 128;
+
+/// Stream `git rev-list` output and size objects in bounded batches, folding
+/// results straight into per-path totals. Only one batch of at most
+/// `max_in_flight` objects is held at a time, so peak memory is bounded by the
+/// budget rather than growing with the whole object set. Returns the summed
+/// on-disk size and reference count for each path.
+fn stream_path_sizes(
+    paths: Vec<String>,
+    max_in_flight: usize,
+) -> (HashMap<PathBuf, u64>, HashMap<PathBuf, u32>) {
+    let mut process = Command::new("git");
+    let process = process.arg("rev-list").arg("--all").arg("--objects");
+    if !paths.is_empty() {
+        process.arg("--").args(paths);
+    };
+
+    let mut child = process
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to execute command git rev-list.");
+    let stdout = child
+        .stdout
+        .take()
+        .expect("Could not get output of command git rev-list.");
+
+    let mut size_sums: HashMap<PathBuf, u64> = HashMap::new();
+    let mut counts: HashMap<PathBuf, u32> = HashMap::new();
+    let mut batch: Vec<(String, PathBuf)> = Vec::new();
+
+    let mut flush = |batch: &mut Vec<(String, PathBuf)>| {
+        if batch.is_empty() {
+            return;
+        }
+        let ids: Vec<String> = batch.iter().map(|(id, _)| id.clone()).collect();
+        let sizes = get_sizes_of_shard(ids);
+        for (id, path) in batch.drain(..) {
+            *counts.entry(path.clone()).or_default() += 1;
+            if let Some(size) = sizes.get(&id) {
+                *size_sums.entry(path).or_default() += size.disk;
+            }
+        }
+    };
+
+    for line in BufReader::new(stdout).lines() {
+        let line = line.expect("Failed to get line from git command output.");
+        if let Some((id, path)) = line.split_once(' ') {
+            batch.push((id.to_owned(), PathBuf::from(path)));
+            if batch.len() >= max_in_flight {
+                flush(&mut batch);
+            }
+        }
+    }
+    flush(&mut batch);
+
+    child.wait().expect("git rev-list did not exit cleanly.");
+    (size_sums, counts)
+}
+
+/// Runs a single `git cat-file` process over one shard of object IDs.
+///
+/// The writer owns its slice and drops stdin when done, exactly as a single
+/// process would, so the child's output buffer can never deadlock the pipe.
+fn get_sizes_of_shard(ids: Vec<String>) -> HashMap<String, ObjectSize> {
+    let mut child = Command::new("git")
         .arg("cat-file")
-        .arg("--batch-check=%(objectname) %(objecttype) %(objectsize:disk)")
+        .arg("--batch-check=%(objectname) %(objecttype) %(objectsize) %(objectsize:disk)")
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .spawn()
         .expect("Failed to execute command git cat-file.");
-    let mut stdin = process.stdin.expect("Could not open child stdin.");
-
-    let ids: Vec<String> = ids.into_iter().cloned().collect(); // copy data for thread
+    let mut stdin = child.stdin.take().expect("Could not open child stdin.");
 
     // Stdin will block when the output buffer gets full, so it needs to be written
     // in a thread:
-    let write_thread = thread::spawn(|| {
+    let write_thread = thread::spawn(move || {
         for obj_id in ids {
             writeln!(stdin, "{}", obj_id).expect("Could not write to child stdin");
         }
         drop(stdin);
     });
 
-    let output = process
+    let output = child
         .stdout
         .take()
         .expect("Could not get output of command git cat-file.");
@@ -83,22 +318,58 @@ fn get_sizes_of_objects(ids: Vec<&String>) -> HashMap<String, u64> {
         let line_split: Vec<&str> = line.split(' ').collect();
 
         // skip non-blob objects
-        if let [id, "blob", size] = &line_split[..] {
+        if let [id, "blob", logical, disk] = &line_split[..] {
             id_map.insert(
                 id.to_string(),
-                size.parse::<u64>().expect("Could not convert size to int."),
+                ObjectSize {
+                    logical: logical.parse().expect("Could not convert size to int."),
+                    disk: disk.parse().expect("Could not convert size to int."),
+                },
             );
         };
     }
     write_thread.join().unwrap();
+    child.wait().expect("git cat-file did not exit cleanly.");
     id_map
 }
 
 fn main() {
     let opt = Opt::from_args();
 
+    // Memory-bounded path: never materialize the full id->path and id->size maps;
+    // stream rev-list and keep only per-path totals. (--unique needs per-object
+    // detail, so it always takes the in-memory path below.)
+    if let Some(max_mem) = opt.max_mem {
+        if !opt.unique {
+            let max_in_flight = (max_mem.0 as usize / EST_BYTES_PER_OBJECT).max(1);
+            let (size_sums, counts) = stream_path_sizes(opt.paths, max_in_flight);
+
+            if opt.directories {
+                let mut root = Directory::new(String::new());
+                for (path, size) in size_sums.iter() {
+                    let components: Vec<Component> = path.components().collect();
+                    root.insert(&components, *size);
+                }
+                root.rollup();
+                root.print(0, opt.max_depth);
+            } else {
+                let sizes: Vec<(&Path, u64)> = size_sums
+                    .iter()
+                    .map(|(path, size)| (path.as_path(), *size))
+                    .collect();
+                print_sizes(
+                    sizes,
+                    &counts,
+                    opt.format,
+                    opt.top,
+                    opt.min_size.map(|s| s.0),
+                );
+            }
+            return;
+        }
+    }
+
     let revs_to_paths = get_revs_for_paths(opt.paths);
-    // println!("{:?}", revs);
     let mut paths_to_count: HashMap<PathBuf, u32> = HashMap::new();
     revs_to_paths.iter().for_each(|(_rev, path)| {
         let previous = paths_to_count.insert(path.clone(), 1);
@@ -106,44 +377,171 @@ fn main() {
             paths_to_count.insert(path.clone(), count + 1);
         }
     });
-    println!("{:#?}", paths_to_count);
 
+    let jobs = opt.jobs.unwrap_or_else(default_jobs);
+    let sizes = get_sizes_of_objects(revs_to_paths.keys().collect(), jobs);
 
-    let sizes = get_sizes_of_objects(revs_to_paths.keys().collect());
+    if opt.unique {
+        print_unique(&sizes, &revs_to_paths);
+        return;
+    }
 
     // This skips directories (they have no size mapping).
     // Filename -> size mapping tuples. Files are present in the list more than once.
     let file_sizes: Vec<(&Path, u64)> = sizes
         .iter()
-        .map(|(id, size)| (revs_to_paths[id].as_path(), *size))
+        .map(|(id, size)| (revs_to_paths[id].as_path(), size.disk))
         .collect();
 
-    // (Filename, size) tuples.
-    let mut file_size_sums: HashMap<&Path, u64> = HashMap::new();
-    for (mut path, size) in file_sizes.into_iter() {
-        if opt.directories {
-            // For file path "foo/bar", add these bytes to path "foo/"
-            let parent = path.parent();
-            path = match parent {
-                Some(parent) => parent,
-                _ => {
-                    eprint!("File has no parent directory: {}", path.display());
-                    continue;
-                }
-            };
+    if opt.directories {
+        // Build the full tree, then roll descendant blobs up into each ancestor
+        // so "foo/bar/baz" also credits "foo/" and "foo/bar/".
+        let mut root = Directory::new(String::new());
+        for (path, size) in file_sizes.into_iter() {
+            let components: Vec<Component> = path.components().collect();
+            root.insert(&components, size);
+        }
+        root.rollup();
+        root.print(0, opt.max_depth);
+    } else {
+        // (Filename, size) tuples. Files are present in the list more than once.
+        let mut file_size_sums: HashMap<&Path, u64> = HashMap::new();
+        for (path, size) in file_sizes.into_iter() {
+            *(file_size_sums.entry(path).or_default()) += size;
         }
+        let sizes: Vec<(&Path, u64)> = file_size_sums.into_iter().collect();
+
+        print_sizes(
+            sizes,
+            &paths_to_count,
+            opt.format,
+            opt.top,
+            opt.min_size.map(|s| s.0),
+        );
+    }
+}
+
+/// Format `1 - reduced/baseline` as a `X.X% saved` string: how much the
+/// `reduced` figure undercuts the `baseline`. A zero baseline reports `0.0%`.
+fn percent_saved(baseline: u64, reduced: u64) -> String {
+    let saved = if baseline == 0 {
+        0.0
+    } else {
+        (1.0 - reduced as f64 / baseline as f64) * 100.0
+    };
+    format!("{:.1}% saved", saved)
+}
+
+/// Report, per path, the logical bytes git would store uncompressed versus the
+/// packed (on-disk) bytes the packfiles actually hold, as a `1 - disk/logical`
+/// compression ratio, plus an overall summary line. Each blob is summed once
+/// per path, so shared content is not double-charged.
+fn print_unique(sizes: &HashMap<String, ObjectSize>, revs_to_paths: &HashMap<String, PathBuf>) {
+    let mut per_path: HashMap<&Path, ObjectSize> = HashMap::new();
+    for (id, size) in sizes.iter() {
+        let entry = per_path.entry(revs_to_paths[id].as_path()).or_default();
+        entry.logical += size.logical;
+        entry.disk += size.disk;
+    }
+
+    let mut rows: Vec<(&Path, ObjectSize)> = per_path.into_iter().collect();
+    rows.sort_by_key(|(_path, size)| size.disk);
 
-        *(file_size_sums.entry(path).or_default()) += size;
+    let mut total = ObjectSize::default();
+    for (path, size) in rows.iter() {
+        total.logical += size.logical;
+        total.disk += size.disk;
+        println!(
+            "{:10}{:10}{:24}{}",
+            ByteSize(size.logical),
+            ByteSize(size.disk),
+            percent_saved(size.logical, size.disk),
+            path.display()
+        );
     }
-    let sizes: Vec<(&Path, u64)> = file_size_sums.into_iter().collect();
 
-    print_sizes(sizes);
+    println!(
+        "{:10}{:10}{:24}(total)",
+        ByteSize(total.logical),
+        ByteSize(total.disk),
+        percent_saved(total.logical, total.disk),
+    );
 }
 
-fn print_sizes(mut sizes: Vec<(&Path, u64)>) {
+/// Quote a field for a CSV row per RFC 4180: wrap in double quotes and double
+/// any embedded double quotes, so commas and quotes in paths stay well-formed.
+fn csv_escape(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
+
+/// Escape a string for embedding in a JSON string literal.
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn print_sizes(
+    mut sizes: Vec<(&Path, u64)>,
+    counts: &HashMap<PathBuf, u32>,
+    format: Format,
+    top: Option<usize>,
+    min_size: Option<u64>,
+) {
     sizes.sort_by_key(|(_path, size)| *size);
-    for file_size in sizes.iter() {
-        // The size needs some padding--a long size is as long as a tabstop
-        println!("{:10}{}", ByteSize(file_size.1), file_size.0.display())
+
+    if let Some(min) = min_size {
+        sizes.retain(|(_path, size)| *size >= min);
+    }
+    // The list is sorted ascending, so the N largest are the final N entries.
+    if let Some(top) = top {
+        let keep = top.min(sizes.len());
+        sizes = sizes.split_off(sizes.len() - keep);
+    }
+
+    let count_of = |path: &Path| counts.get(path).copied().unwrap_or(0);
+
+    match format {
+        Format::Human => {
+            for (path, size) in sizes.iter() {
+                // The size needs some padding--a long size is as long as a tabstop
+                println!("{:10}{}", ByteSize(*size), path.display())
+            }
+        }
+        Format::Csv => {
+            println!("path,size,count");
+            for (path, size) in sizes.iter() {
+                println!(
+                    "{},{},{}",
+                    csv_escape(&path.display().to_string()),
+                    size,
+                    count_of(path)
+                );
+            }
+        }
+        Format::Json => {
+            let entries: Vec<String> = sizes
+                .iter()
+                .map(|(path, size)| {
+                    format!(
+                        "{{\"path\":\"{}\",\"size\":{},\"count\":{}}}",
+                        json_escape(&path.display().to_string()),
+                        size,
+                        count_of(path)
+                    )
+                })
+                .collect();
+            println!("[{}]", entries.join(","));
+        }
     }
 }
\ No newline at end of file